@@ -1,14 +1,18 @@
 use avif_decode::Decoder;
-use image::codecs::avif;
 use image::codecs::gif;
 use image::codecs::webp;
-use image::{AnimationDecoder, DynamicImage, ImageEncoder, ImageFormat, RgbaImage};
+use image::metadata::Orientation;
+use image::{
+    AnimationDecoder, Delay, DynamicImage, Frame, ImageDecoder, ImageFormat, ImageReader, RgbaImage,
+};
 use lodepng::Bitmap;
 use rgb::{ComponentBytes, RGB8, RGBA8};
-use snafu::{ResultExt, Snafu};
+use snafu::{ensure, ResultExt, Snafu};
 use std::{
+    collections::HashMap,
     ffi::OsStr,
-    io::{BufRead, Seek},
+    io::{BufRead, Cursor, Seek, Write},
+    time::Duration,
 };
 
 #[derive(Debug, Snafu)]
@@ -33,8 +37,37 @@ pub enum ImageError {
         category: String,
         source: lodepng::Error,
     },
+    #[snafu(display("Handle image fail, category:{category}, message:{source}"))]
+    Ravif {
+        category: String,
+        source: ravif::Error,
+    },
+    #[snafu(display("Handle image fail, category:{category}, message:{source}"))]
+    Usvg {
+        category: String,
+        source: usvg::Error,
+    },
+    #[snafu(display("Handle image fail, category:svg, message:{message}"))]
+    SvgUnsupported { message: String },
+    #[snafu(display("Palette size {size} is invalid, it should be 2-256"))]
+    PaletteSize { size: u32 },
+    #[snafu(display("Handle image fail, category:{category}, message:{source}"))]
+    GifEncode {
+        category: String,
+        source: ::gif::EncodingError,
+    },
+    #[snafu(display("Encode option {key} is invalid, value:{value}"))]
+    EncodeOption { key: String, value: String },
+    #[snafu(display("Quality {quality} is invalid, it should be 0-100"))]
+    QualityInvalid { quality: u8 },
+    #[snafu(display("Frame {index} is not found"))]
+    FrameNotFound { index: usize },
+    #[snafu(display("Handle image fail, category:jpegtran, message:{message}"))]
+    Jpegtran { message: String },
     #[snafu(display("Handle image fail, category:mozjpeg, message:unknown"))]
     Mozjpeg {},
+    #[snafu(display("Handle image fail, category:metadata, message:{message}"))]
+    Metadata { message: String },
     #[snafu(display("Io fail, {source}"))]
     Io { source: std::io::Error },
     #[snafu(display("Handle image fail"))]
@@ -43,6 +76,125 @@ pub enum ImageError {
 
 type Result<T, E = ImageError> = std::result::Result<T, E>;
 
+/// Check the quality is in the range 0-100.
+pub fn check_quality(quality: u8) -> Result<()> {
+    ensure!(quality <= 100, QualityInvalidSnafu { quality });
+    Ok(())
+}
+
+/// The encoder specific options, the key is prefixed with the format.
+/// The recognized keys are listed in `ENCODE_OPTION_KEYS`,
+/// the others are ignored with a warning.
+pub type EncodeOptions = HashMap<String, String>;
+
+/// The recognized keys of encode options:
+/// - `png.dithering`: the dithering level of quantization, 0-1, default 1
+/// - `png.min_quality`: the min quality of quantization 0-100, it fails if it can't be met, default 0
+/// - `png.speed`: the speed of quantization 1-10, the slower speed makes the smaller output, default 4
+/// - `png.quantizer`: `imagequant` or the faster `neuquant` of lower quality, default imagequant,
+///   the dithering, min quality and speed are only for imagequant and rejected by neuquant
+/// - `avif.color_model`: the internal color model, `ycbcr` or `rgb`
+/// - `avif.alpha_color_mode`: `dirty`, `clean` or `premultiplied`
+/// - `avif.threads`: the number of threads for encoding
+/// - `avif.monochrome`: encode grayscale image without chroma planes, default false
+/// - `jpeg.progressive`: whether to encode as progressive jpeg, default true
+/// - `jpeg.subsampling`: the chroma subsampling `444`, `422` or `420`, default 420
+/// - `jpeg.smoothing`: the smoothing factor 0-100, 0 means no smoothing
+pub const ENCODE_OPTION_KEYS: [&str; 11] = [
+    "png.dithering",
+    "png.min_quality",
+    "png.speed",
+    "png.quantizer",
+    "avif.color_model",
+    "avif.alpha_color_mode",
+    "avif.threads",
+    "avif.monochrome",
+    "jpeg.progressive",
+    "jpeg.subsampling",
+    "jpeg.smoothing",
+];
+
+fn warn_unknown_options(options: &EncodeOptions) {
+    for key in options.keys() {
+        if !ENCODE_OPTION_KEYS.contains(&key.as_str()) {
+            log::warn!("encode option {key} is unknown, it is ignored");
+        }
+    }
+}
+
+// 获取指定的选项并转换类型，转换失败则返回出错
+fn encode_option<T: std::str::FromStr>(options: &EncodeOptions, key: &str) -> Result<Option<T>> {
+    let Some(value) = options.get(key) else {
+        return Ok(None);
+    };
+    let value = value.trim();
+    match value.parse::<T>() {
+        Ok(v) => Ok(Some(v)),
+        Err(_) => EncodeOptionSnafu { key, value }.fail(),
+    }
+}
+
+// 与ravif一致的质量与量化参数的对应关系
+fn quality_to_quantizer(quality: u8) -> usize {
+    let q = quality as f32 / 100.;
+    let x = if q >= 0.82 {
+        (1. - q) * 2.6
+    } else if q > 0.25 {
+        q.mul_add(-0.5, 1. - 0.125)
+    } else {
+        1. - q
+    };
+    (x * 255.).round() as usize
+}
+
+// 将单个平面编码为单色(YUV400)的av1数据
+fn encode_av1_plane(
+    width: usize,
+    height: usize,
+    plane: &[u8],
+    quality: u8,
+    speed: u8,
+) -> Result<Vec<u8>, ravif::Error> {
+    let quantizer = quality_to_quantizer(quality);
+    let mut config = rav1e::EncoderConfig::with_speed_preset(speed);
+    config.width = width;
+    config.height = height;
+    config.time_base = rav1e::prelude::Rational::new(1, 1);
+    config.chroma_sampling = rav1e::prelude::ChromaSampling::Cs400;
+    config.pixel_range = rav1e::prelude::PixelRange::Full;
+    config.still_picture = true;
+    config.min_key_frame_interval = 0;
+    config.max_key_frame_interval = 0;
+    config.quantizer = quantizer;
+    config.min_quantizer = quantizer as u8;
+    config.tune = rav1e::prelude::Tune::Psychovisual;
+
+    let mut ctx: rav1e::Context<u8> = rav1e::Config::new()
+        .with_encoder_config(config)
+        .new_context()?;
+    let mut frame = ctx.new_frame();
+    frame.planes[0].copy_from_raw_u8(plane, width, 1);
+    ctx.send_frame(frame)?;
+    ctx.flush();
+
+    let mut data = Vec::new();
+    loop {
+        match ctx.receive_packet() {
+            Ok(mut packet) => {
+                if packet.frame_type == rav1e::prelude::FrameType::KEY {
+                    data.append(&mut packet.data);
+                }
+            }
+            Err(rav1e::EncoderStatus::Encoded | rav1e::EncoderStatus::LimitReached) => break,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(data)
+}
+
+/// The max attempts of encoding with different speeds.
+pub const MAX_SPEED_ATTEMPTS: usize = 4;
+
 pub struct ImageInfo {
     // rgba像素
     pub buffer: Vec<RGBA8>,
@@ -162,7 +314,681 @@ pub fn load<R: BufRead + Seek>(r: R, ext: &str) -> Result<ImageInfo> {
     Ok(img.into())
 }
 
+// 从exif(tiff格式)的IFD1中获取缩略图数据
+fn exif_thumbnail_data(exif: &[u8]) -> Option<&[u8]> {
+    let big_endian = match exif.get(0..2)? {
+        b"II" => false,
+        b"MM" => true,
+        _ => return None,
+    };
+    let u16_at = |offset: usize| {
+        let buf: [u8; 2] = exif.get(offset..offset.checked_add(2)?)?.try_into().ok()?;
+        Some(if big_endian {
+            u16::from_be_bytes(buf)
+        } else {
+            u16::from_le_bytes(buf)
+        })
+    };
+    let u32_at = |offset: usize| {
+        let buf: [u8; 4] = exif.get(offset..offset.checked_add(4)?)?.try_into().ok()?;
+        Some(if big_endian {
+            u32::from_be_bytes(buf)
+        } else {
+            u32::from_le_bytes(buf)
+        })
+    };
+    let ifd0 = u32_at(4)? as usize;
+    let ifd1 = u32_at(ifd0 + 2 + u16_at(ifd0)? as usize * 12)? as usize;
+    if ifd1 == 0 {
+        return None;
+    }
+    let mut offset = None;
+    let mut length = None;
+    for index in 0..u16_at(ifd1)? as usize {
+        let entry = ifd1 + 2 + index * 12;
+        match u16_at(entry)? {
+            // JPEGInterchangeFormat
+            0x0201 => offset = u32_at(entry + 8),
+            // JPEGInterchangeFormatLength
+            0x0202 => length = u32_at(entry + 8),
+            _ => {}
+        }
+    }
+    let offset = offset? as usize;
+    exif.get(offset..offset.checked_add(length? as usize)?)
+}
+
+/// Read the thumbnail embedded in the exif of image, only the headers are parsed
+/// and the full image is not decoded. It returns `None` if there is no thumbnail.
+pub fn exif_thumbnail(data: &[u8]) -> Result<Option<DynamicImage>> {
+    let mut decoder = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .context(IoSnafu {})?
+        .into_decoder()
+        .context(ImageSnafu {
+            category: "exif_decoder",
+        })?;
+    let exif = decoder.exif_metadata().context(ImageSnafu {
+        category: "exif_metadata",
+    })?;
+    let Some(thumbnail) = exif.as_deref().and_then(exif_thumbnail_data) else {
+        return Ok(None);
+    };
+    let di =
+        image::load_from_memory_with_format(thumbnail, ImageFormat::Jpeg).context(ImageSnafu {
+            category: "exif_thumbnail",
+        })?;
+    Ok(Some(di))
+}
+
+/// Read the exif orientation of image, only the headers are parsed.
+/// It returns `NoTransforms` if there is no orientation.
+pub fn exif_orientation(data: &[u8]) -> Result<Orientation> {
+    let mut decoder = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .context(IoSnafu {})?
+        .into_decoder()
+        .context(ImageSnafu {
+            category: "exif_decoder",
+        })?;
+    decoder.orientation().context(ImageSnafu {
+        category: "exif_orientation",
+    })
+}
+
+// libjpeg出错时通过unwind返回，避免默认处理直接退出进程
+extern "C-unwind" fn jpeg_error_exit(cinfo: &mut mozjpeg_sys::jpeg_common_struct) {
+    let code = unsafe { cinfo.err.as_ref() }
+        .map(|err| err.msg_code)
+        .unwrap_or_default();
+    std::panic::resume_unwind(Box::new(code));
+}
+
+extern "C-unwind" fn jpeg_emit_message(_cinfo: &mut mozjpeg_sys::jpeg_common_struct, _level: i32) {}
+
+// 转码的上下文，drop时释放libjpeg的资源
+struct Jpegtran {
+    src: Box<mozjpeg_sys::jpeg_decompress_struct>,
+    dst: Box<mozjpeg_sys::jpeg_compress_struct>,
+    outbuffer: *mut u8,
+    outsize: std::os::raw::c_ulong,
+    // 需要在src与dst之后释放
+    _err: Box<mozjpeg_sys::jpeg_error_mgr>,
+}
+
+impl Jpegtran {
+    fn new() -> Self {
+        unsafe {
+            let mut err: Box<mozjpeg_sys::jpeg_error_mgr> = Box::new(std::mem::zeroed());
+            mozjpeg_sys::jpeg_std_error(&mut err);
+            err.error_exit = Some(jpeg_error_exit);
+            err.emit_message = Some(jpeg_emit_message);
+            let mut src: Box<mozjpeg_sys::jpeg_decompress_struct> = Box::new(std::mem::zeroed());
+            src.common.err = err.as_mut();
+            mozjpeg_sys::jpeg_create_decompress(src.as_mut());
+            let mut dst: Box<mozjpeg_sys::jpeg_compress_struct> = Box::new(std::mem::zeroed());
+            dst.common.err = err.as_mut();
+            mozjpeg_sys::jpeg_create_compress(dst.as_mut());
+            Jpegtran {
+                src,
+                dst,
+                outbuffer: std::ptr::null_mut(),
+                outsize: 0,
+                _err: err,
+            }
+        }
+    }
+    fn transcode(&mut self, data: &[u8], keep: impl Fn(u8, &[u8]) -> bool) -> Vec<u8> {
+        unsafe {
+            let src = self.src.as_mut();
+            let dst = self.dst.as_mut();
+            mozjpeg_sys::jpeg_mem_src(src, data.as_ptr(), data.len() as std::os::raw::c_ulong);
+            // 保留APP与COM标记，如exif与icc
+            for marker in (0xe0..=0xef).chain([0xfe]) {
+                mozjpeg_sys::jpeg_save_markers(src, marker, 0xffff);
+            }
+            mozjpeg_sys::jpeg_read_header(src, 1);
+            let coefficients = mozjpeg_sys::jpeg_read_coefficients(src);
+            mozjpeg_sys::jpeg_copy_critical_parameters(src, dst);
+            dst.optimize_coding = 1;
+            mozjpeg_sys::jpeg_simple_progression(dst);
+            mozjpeg_sys::jpeg_mem_dest(dst, &mut self.outbuffer, &mut self.outsize);
+            mozjpeg_sys::jpeg_write_coefficients(dst, coefficients);
+
+            let mut marker = src.marker_list;
+            while let Some(item) = marker.as_ref() {
+                let data = std::slice::from_raw_parts(item.data, item.data_length as usize);
+                // JFIF与Adobe标记由编码器生成
+                let generated = (item.marker == 0xe0
+                    && data.starts_with(b"JFIF\0")
+                    && dst.write_JFIF_header != 0)
+                    || (item.marker == 0xee
+                        && data.starts_with(b"Adobe")
+                        && dst.write_Adobe_marker != 0);
+                if !generated && keep(item.marker, data) {
+                    mozjpeg_sys::jpeg_write_marker(
+                        dst,
+                        item.marker as i32,
+                        item.data,
+                        item.data_length,
+                    );
+                }
+                marker = item.next;
+            }
+            mozjpeg_sys::jpeg_finish_compress(dst);
+            mozjpeg_sys::jpeg_finish_decompress(src);
+            std::slice::from_raw_parts(self.outbuffer, self.outsize as usize).to_vec()
+        }
+    }
+}
+
+impl Drop for Jpegtran {
+    fn drop(&mut self) {
+        unsafe {
+            mozjpeg_sys::jpeg_destroy_compress(self.dst.as_mut());
+            mozjpeg_sys::jpeg_destroy_decompress(self.src.as_mut());
+            if !self.outbuffer.is_null() {
+                libc::free(self.outbuffer as *mut libc::c_void);
+            }
+        }
+    }
+}
+
+/// Optimize jpeg losslessly like jpegtran, the dct coefficients are copied
+/// without decoding pixels, only the huffman tables are optimized and the scans
+/// are changed to progressive, so there is no generation loss. The markers such as
+/// exif and icc profile are kept.
+pub fn jpeg_lossless(data: &[u8]) -> Result<Vec<u8>> {
+    transcode_jpeg(data, |_, _| true)
+}
+
+/// Optimize jpeg losslessly as `jpeg_lossless`, but the exif, xmp and comment
+/// markers are dropped, the icc profile is kept if `preserve_icc` is true.
+pub fn jpeg_lossless_stripped(data: &[u8], preserve_icc: bool) -> Result<Vec<u8>> {
+    transcode_jpeg(data, |marker, data| {
+        preserve_icc && marker == 0xe2 && data.starts_with(ICC_MARKER)
+    })
+}
+
+fn transcode_jpeg(data: &[u8], keep: impl Fn(u8, &[u8]) -> bool) -> Result<Vec<u8>> {
+    // keep只用于判断标记，unwind后无状态需要恢复
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        Jpegtran::new().transcode(data, keep)
+    }));
+    match result {
+        Ok(data) => Ok(data),
+        Err(err) => {
+            let code = err.downcast_ref::<i32>().copied().unwrap_or_default();
+            JpegtranSnafu {
+                message: format!("libjpeg error code {code}"),
+            }
+            .fail()
+        }
+    }
+}
+
+/// Read the icc profile of image, only the headers are parsed.
+pub fn icc_profile(data: &[u8]) -> Result<Option<Vec<u8>>> {
+    let mut decoder = ImageReader::new(Cursor::new(data))
+        .with_guessed_format()
+        .context(IoSnafu {})?
+        .into_decoder()
+        .context(ImageSnafu {
+            category: "icc_decoder",
+        })?;
+    decoder.icc_profile().context(ImageSnafu {
+        category: "icc_profile",
+    })
+}
+
+const ICC_MARKER: &[u8] = b"ICC_PROFILE\0";
+
+fn metadata_error(message: &str) -> ImageError {
+    ImageError::Metadata {
+        message: message.to_string(),
+    }
+}
+
+// jpeg的icc profile保存在APP2中，超出长度则分段
+fn embed_jpeg_icc(data: &[u8], icc: &[u8]) -> Result<Vec<u8>> {
+    ensure!(
+        data.starts_with(&[0xff, 0xd8]),
+        MetadataSnafu {
+            message: "jpeg data is invalid"
+        }
+    );
+    let chunks: Vec<&[u8]> = icc.chunks(65535 - 2 - ICC_MARKER.len() - 2).collect();
+    ensure!(
+        chunks.len() <= 255,
+        MetadataSnafu {
+            message: "icc profile is too large"
+        }
+    );
+    // 插入在SOI以及JFIF标记之后
+    let mut offset = 2;
+    if data.get(2..4) == Some(&[0xff, 0xe0]) {
+        let length = u16::from_be_bytes([data[4], data[5]]) as usize;
+        offset += 2 + length;
+    }
+    let head = data
+        .get(..offset)
+        .ok_or_else(|| metadata_error("jpeg data is invalid"))?;
+    let mut result = Vec::with_capacity(data.len() + icc.len() + chunks.len() * 18);
+    result.extend_from_slice(head);
+    for (index, chunk) in chunks.iter().enumerate() {
+        let length = (2 + ICC_MARKER.len() + 2 + chunk.len()) as u16;
+        result.extend_from_slice(&[0xff, 0xe2]);
+        result.extend_from_slice(&length.to_be_bytes());
+        result.extend_from_slice(ICC_MARKER);
+        result.extend_from_slice(&[index as u8 + 1, chunks.len() as u8]);
+        result.extend_from_slice(chunk);
+    }
+    result.extend_from_slice(&data[offset..]);
+    Ok(result)
+}
+
+// png的icc profile保存在IHDR之后的iCCP中
+fn embed_png_icc(data: &[u8], icc: &[u8]) -> Result<Vec<u8>> {
+    // 签名8字节，IHDR共25字节
+    let offset = 33;
+    ensure!(
+        data.len() > offset && data.get(12..16) == Some(b"IHDR"),
+        MetadataSnafu {
+            message: "png data is invalid"
+        }
+    );
+    let mut encoder =
+        flate2::write::ZlibEncoder::new(b"icc\0\0".to_vec(), flate2::Compression::best());
+    encoder.write_all(icc).context(IoSnafu {})?;
+    let chunk_data = encoder.finish().context(IoSnafu {})?;
+    let mut crc = flate2::Crc::new();
+    crc.update(b"iCCP");
+    crc.update(&chunk_data);
+
+    let mut result = Vec::with_capacity(data.len() + chunk_data.len() + 12);
+    result.extend_from_slice(&data[..offset]);
+    result.extend_from_slice(&(chunk_data.len() as u32).to_be_bytes());
+    result.extend_from_slice(b"iCCP");
+    result.extend_from_slice(&chunk_data);
+    result.extend_from_slice(&crc.sum().to_be_bytes());
+    result.extend_from_slice(&data[offset..]);
+    Ok(result)
+}
+
+// webp的icc profile需要扩展格式，在VP8X之后添加ICCP
+fn embed_webp_icc(data: &[u8], icc: &[u8]) -> Result<Vec<u8>> {
+    ensure!(
+        data.len() > 30 && data.starts_with(b"RIFF") && data.get(8..12) == Some(b"WEBP"),
+        MetadataSnafu {
+            message: "webp data is invalid"
+        }
+    );
+    let mut iccp = b"ICCP".to_vec();
+    iccp.extend_from_slice(&(icc.len() as u32).to_le_bytes());
+    iccp.extend_from_slice(icc);
+    if icc.len() % 2 == 1 {
+        iccp.push(0);
+    }
+    let mut body = b"WEBP".to_vec();
+    match &data[12..16] {
+        b"VP8X" => {
+            // 已是扩展格式，设置icc标记
+            body.extend_from_slice(&data[12..30]);
+            body[12] |= 0x20;
+            body.extend_from_slice(&iccp);
+            body.extend_from_slice(&data[30..]);
+        }
+        format => {
+            let (width, height, alpha) = if format == b"VP8L" {
+                let bits = u32::from_le_bytes([data[21], data[22], data[23], data[24]]);
+                (
+                    (bits & 0x3fff) + 1,
+                    ((bits >> 14) & 0x3fff) + 1,
+                    (bits >> 28) & 1 == 1,
+                )
+            } else if format == b"VP8 " {
+                (
+                    u16::from_le_bytes([data[26], data[27]]) as u32 & 0x3fff,
+                    u16::from_le_bytes([data[28], data[29]]) as u32 & 0x3fff,
+                    false,
+                )
+            } else {
+                return Err(metadata_error("webp data is invalid"));
+            };
+            let flags = if alpha { 0x30 } else { 0x20 };
+            body.extend_from_slice(b"VP8X");
+            body.extend_from_slice(&10_u32.to_le_bytes());
+            body.extend_from_slice(&[flags, 0, 0, 0]);
+            body.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+            body.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+            body.extend_from_slice(&iccp);
+            body.extend_from_slice(&data[12..]);
+        }
+    }
+    let mut result = b"RIFF".to_vec();
+    result.extend_from_slice(&(body.len() as u32).to_le_bytes());
+    result.extend_from_slice(&body);
+    Ok(result)
+}
+
+/// Embed the icc profile into the encoded jpeg, png or webp data.
+/// The avif encoder has no support of icc profile, so the avif and
+/// the other formats are returned unchanged.
+pub fn embed_icc_profile(data: &[u8], ext: &str, icc: &[u8]) -> Result<Vec<u8>> {
+    match ext {
+        "jpeg" | "jpg" => embed_jpeg_icc(data, icc),
+        "png" => embed_png_icc(data, icc),
+        "webp" => embed_webp_icc(data, icc),
+        _ => Ok(data.to_vec()),
+    }
+}
+
+/// Get the preview of image within the max size, the exif thumbnail is used
+/// if it's large enough, otherwise the full image is decoded and resized.
+pub fn preview(data: &[u8], max_size: u32) -> Result<DynamicImage> {
+    if let Some(di) = exif_thumbnail(data)? {
+        if di.width().max(di.height()) >= max_size {
+            return Ok(di.thumbnail(max_size, max_size));
+        }
+    }
+    let di = image::load_from_memory(data).context(ImageSnafu {
+        category: "preview_decode",
+    })?;
+    Ok(di.thumbnail(max_size, max_size))
+}
+
+/// Decode the first frame of gif, the frame is composited over the
+/// logical screen with its offset and disposal, so a partial frame is placed correctly.
+pub fn gif_first_frame<R: BufRead + Seek>(r: R) -> Result<DynamicImage> {
+    frame_at(r, ImageFormat::Gif, 0)
+}
+
+/// Decode the frame of animated gif or webp by index, the frame is composited
+/// over the canvas. The still image is treated as a single frame.
+pub fn frame_at<R: BufRead + Seek>(
+    r: R,
+    format: ImageFormat,
+    index: usize,
+) -> Result<DynamicImage> {
+    let frames = match format {
+        ImageFormat::Gif => gif::GifDecoder::new(r)
+            .context(ImageSnafu {
+                category: "gif_decode",
+            })?
+            .into_frames(),
+        ImageFormat::WebP => {
+            let decoder = webp::WebPDecoder::new(r).context(ImageSnafu {
+                category: "webp_decode",
+            })?;
+            if decoder.has_animation() {
+                decoder.into_frames()
+            } else {
+                ensure!(index == 0, FrameNotFoundSnafu { index });
+                return DynamicImage::from_decoder(decoder).context(ImageSnafu {
+                    category: "webp_decode",
+                });
+            }
+        }
+        _ => {
+            ensure!(index == 0, FrameNotFoundSnafu { index });
+            return image::load(r, format).context(ImageSnafu { category: "load" });
+        }
+    };
+    let frame = frames
+        .into_iter()
+        .nth(index)
+        .ok_or(ImageError::FrameNotFound { index })?
+        .context(ImageSnafu { category: "frame" })?;
+    Ok(DynamicImage::ImageRgba8(frame.into_buffer()))
+}
+
+/// Decode all frames of animated gif or webp, each frame is composited
+/// over the canvas and keeps its delay. The still image is a single frame.
+pub fn decode_frames<R: BufRead + Seek>(r: R, format: ImageFormat) -> Result<Vec<Frame>> {
+    let frames = match format {
+        ImageFormat::Gif => gif::GifDecoder::new(r)
+            .context(ImageSnafu {
+                category: "gif_decode",
+            })?
+            .into_frames(),
+        ImageFormat::WebP => {
+            let decoder = webp::WebPDecoder::new(r).context(ImageSnafu {
+                category: "webp_decode",
+            })?;
+            if !decoder.has_animation() {
+                let img = DynamicImage::from_decoder(decoder).context(ImageSnafu {
+                    category: "webp_decode",
+                })?;
+                return Ok(vec![Frame::new(img.to_rgba8())]);
+            }
+            decoder.into_frames()
+        }
+        _ => {
+            let img = image::load(r, format).context(ImageSnafu { category: "load" })?;
+            return Ok(vec![Frame::new(img.to_rgba8())]);
+        }
+    };
+    frames
+        .collect_frames()
+        .context(ImageSnafu { category: "frame" })
+}
+
+/// Encode the frames to animated webp, the frames should be the composited canvases
+/// as the result of `decode_frames`, so the offsets of frames are ignored.
+/// The loop count is the number of times to play, 0 means infinite.
+/// The webp encoder is lossless only, the colors of each frame are
+/// quantized before encoding if the quality is less than 100.
+pub fn to_animated_webp(frames: &[Frame], loop_count: u32, quality: u8) -> Result<Vec<u8>> {
+    check_quality(quality)?;
+    ensure!(!frames.is_empty(), FrameNotFoundSnafu { index: 0_usize });
+    let (width, height) = frames.iter().fold((1, 1), |(width, height), frame| {
+        let (w, h) = frame.buffer().dimensions();
+        (width.max(w), height.max(h))
+    });
+    let u24 = |value: u32| value.min(0xff_ffff).to_le_bytes()[..3].to_vec();
+    let chunk = |name: &[u8], payload: Vec<u8>| {
+        let mut data = name.to_vec();
+        data.extend((payload.len() as u32).to_le_bytes());
+        data.extend(&payload);
+        // chunk按偶数字节对齐
+        if payload.len() % 2 == 1 {
+            data.push(0);
+        }
+        data
+    };
+
+    // 动画以及透明通道的标记
+    let mut vp8x = vec![0x12, 0, 0, 0];
+    vp8x.extend(u24(width - 1));
+    vp8x.extend(u24(height - 1));
+    let mut body = b"WEBP".to_vec();
+    body.extend(chunk(b"VP8X", vp8x));
+    // 背景色为透明
+    let mut anim = vec![0, 0, 0, 0];
+    anim.extend((loop_count.min(u16::MAX as u32) as u16).to_le_bytes());
+    body.extend(chunk(b"ANIM", anim));
+    for frame in frames {
+        let buffer = frame.buffer();
+        let info: ImageInfo = buffer.clone().into();
+        let data = if quality >= 100 {
+            info.to_webp()?
+        } else {
+            info.to_webp_quantized(quality)?
+        };
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        let mut anmf = [
+            u24(0),
+            u24(0),
+            u24(buffer.width() - 1),
+            u24(buffer.height() - 1),
+            u24(numer / denom.max(1)),
+        ]
+        .concat();
+        // 不混合直接覆盖，也不需要处置
+        anmf.push(0x02);
+        // 去除RIFF的头部，只保留VP8L的chunk
+        anmf.extend(&data[12..]);
+        body.extend(chunk(b"ANMF", anmf));
+    }
+    Ok(chunk(b"RIFF", body))
+}
+
+/// The frame count, loop count and total duration of animated image.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AnimationInfo {
+    pub frames: usize,
+    /// The number of times to play, 0 means infinite.
+    pub loop_count: u32,
+    pub duration: Duration,
+}
+
+/// Probe the animation of gif, webp or apng, only the headers of frames are
+/// read and the pixels are not decoded. It returns `None` if the image is still,
+/// the format is not supported or the data is invalid.
+pub fn probe_animation(data: &[u8], ext: &str) -> Option<AnimationInfo> {
+    let info = match ext {
+        "gif" => probe_gif_animation(data),
+        "webp" => probe_webp_animation(data),
+        "png" | "apng" => probe_png_animation(data),
+        _ => None,
+    }?;
+    (info.frames > 1).then_some(info)
+}
+
+fn probe_gif_animation(data: &[u8]) -> Option<AnimationInfo> {
+    let mut options = ::gif::DecodeOptions::new();
+    // 只读取帧信息，不解码lzw数据
+    options.skip_frame_decoding(true);
+    let mut decoder = options.read_info(data).ok()?;
+    let mut info = AnimationInfo::default();
+    while let Some(frame) = decoder.read_next_frame().ok()? {
+        info.frames += 1;
+        // gif的延时单位为10ms
+        info.duration += Duration::from_millis(frame.delay as u64 * 10);
+    }
+    // 重复次数不包括首次播放
+    info.loop_count = match decoder.repeat() {
+        ::gif::Repeat::Infinite => 0,
+        ::gif::Repeat::Finite(count) => count as u32 + 1,
+    };
+    Some(info)
+}
+
+fn probe_webp_animation(data: &[u8]) -> Option<AnimationInfo> {
+    if !data.starts_with(b"RIFF") || data.get(8..12)? != b"WEBP" {
+        return None;
+    }
+    let mut info = AnimationInfo::default();
+    let mut offset = 12;
+    while let Some(header) = data.get(offset..offset + 8) {
+        let size = u32::from_le_bytes(header[4..8].try_into().ok()?) as usize;
+        let payload = data.get(offset + 8..offset + 8 + size)?;
+        match &header[..4] {
+            b"ANIM" => {
+                info.loop_count = u16::from_le_bytes(payload.get(4..6)?.try_into().ok()?) as u32;
+            }
+            b"ANMF" => {
+                info.frames += 1;
+                let delay = payload.get(12..15)?;
+                let delay = u32::from_le_bytes([delay[0], delay[1], delay[2], 0]);
+                info.duration += Duration::from_millis(delay as u64);
+            }
+            _ => {}
+        }
+        // chunk按偶数字节对齐
+        offset += 8 + size + size % 2;
+    }
+    Some(info)
+}
+
+fn probe_png_animation(data: &[u8]) -> Option<AnimationInfo> {
+    if !data.starts_with(b"\x89PNG\r\n\x1a\n") {
+        return None;
+    }
+    let mut info: Option<AnimationInfo> = None;
+    let mut duration = Duration::ZERO;
+    let mut offset = 8;
+    while let Some(header) = data.get(offset..offset + 8) {
+        let size = u32::from_be_bytes(header[..4].try_into().ok()?) as usize;
+        let payload = data.get(offset + 8..offset + 8 + size)?;
+        let read_u32 = |start: usize| -> Option<u32> {
+            Some(u32::from_be_bytes(
+                payload.get(start..start + 4)?.try_into().ok()?,
+            ))
+        };
+        match &header[4..] {
+            b"acTL" => {
+                info = Some(AnimationInfo {
+                    frames: read_u32(0)? as usize,
+                    loop_count: read_u32(4)?,
+                    ..Default::default()
+                });
+            }
+            b"fcTL" => {
+                let delay = payload.get(20..24)?;
+                let numerator = u16::from_be_bytes([delay[0], delay[1]]);
+                // 分母为0时按100处理
+                let denominator = match u16::from_be_bytes([delay[2], delay[3]]) {
+                    0 => 100,
+                    value => value,
+                };
+                duration += Duration::from_secs_f64(numerator as f64 / denominator as f64);
+            }
+            b"IEND" => break,
+            _ => {}
+        }
+        // 长度、类型、数据以及crc
+        offset += 12 + size;
+    }
+    info.map(|info| AnimationInfo { duration, ..info })
+}
+
+/// Minify the svg without rasterizing, the metadata is stripped,
+/// the transforms are collapsed and the numbers are rounded to the precision.
+/// The text is not supported as it can't be kept without the fonts,
+/// so the svg with text returns an error instead of dropping it.
+pub fn minify_svg(data: &[u8], precision: u8) -> Result<Vec<u8>> {
+    let data = if data.starts_with(&[0x1f, 0x8b]) {
+        usvg::decompress_svgz(data).context(UsvgSnafu {
+            category: "svg_decompress",
+        })?
+    } else {
+        data.to_vec()
+    };
+    // 无法解析的交由usvg返回出错信息
+    if let Ok(doc) = roxmltree::Document::parse(&String::from_utf8_lossy(&data)) {
+        ensure!(
+            !doc.descendants().any(|node| node.has_tag_name("text")),
+            SvgUnsupportedSnafu {
+                message: "text is not supported"
+            }
+        );
+    }
+    let tree = usvg::Tree::from_data(&data, &usvg::Options::default()).context(UsvgSnafu {
+        category: "svg_parse",
+    })?;
+    let opt = usvg::WriteOptions {
+        coordinates_precision: precision,
+        transforms_precision: precision,
+        indent: usvg::Indent::None,
+        attributes_indent: usvg::Indent::None,
+        ..Default::default()
+    };
+    Ok(tree.to_string(&opt).into_bytes())
+}
+
 pub fn to_gif<R>(r: R, speed: u8) -> Result<Vec<u8>>
+where
+    R: std::io::BufRead,
+    R: std::io::Seek,
+{
+    to_gif_with_playback_rate(r, speed, 1.0)
+}
+
+/// Optimize the gif and change the playback speed by the rate,
+/// e.g. 2.0 plays twice as fast. The delay of each frame is kept
+/// if the rate is 1.0 and the frames are composited as the source.
+pub fn to_gif_with_playback_rate<R>(r: R, speed: u8, rate: f32) -> Result<Vec<u8>>
 where
     R: std::io::BufRead,
     R: std::io::Seek,
@@ -170,7 +996,19 @@ where
     let decoder = gif::GifDecoder::new(r).context(ImageSnafu {
         category: "gif_decode",
     })?;
-    let frames = decoder.into_frames();
+    let frames = decoder.into_frames().map(|result| {
+        result.map(|frame| {
+            if rate <= 0.0 || rate == 1.0 {
+                return frame;
+            }
+            let (numer, denom) = frame.delay().numer_denom_ms();
+            let ms = numer as f32 / denom as f32 / rate;
+            let delay = Delay::from_numer_denom_ms((ms * 1000.0).round() as u32, 1000);
+            let left = frame.left();
+            let top = frame.top();
+            Frame::from_parts(frame.into_buffer(), left, top, delay)
+        })
+    });
 
     let mut w = Vec::new();
 
@@ -181,17 +1019,104 @@ where
             .context(ImageSnafu {
                 category: "gif_set_repeat",
             })?;
+        encoder.try_encode_frames(frames).context(ImageSnafu {
+            category: "git_encode",
+        })?;
+    }
+
+    Ok(w)
+}
+
+/// Optimize the gif with the palette of each frame limited to the max colors,
+/// the frames are quantized with the dithering level 0-1. The max colors
+/// should be 2-256, and the smaller palette makes the smaller output.
+pub fn to_gif_with_palette<R>(r: R, max_colors: u32, dithering: f32) -> Result<Vec<u8>>
+where
+    R: std::io::BufRead,
+    R: std::io::Seek,
+{
+    ensure!(
+        (2..=256).contains(&max_colors),
+        PaletteSizeSnafu { size: max_colors }
+    );
+    let decoder = gif::GifDecoder::new(r).context(ImageSnafu {
+        category: "gif_decode",
+    })?;
+    let (width, height) = decoder.dimensions();
+    let frames = decoder.into_frames();
+
+    let mut w = Vec::new();
+    {
+        let mut encoder = ::gif::Encoder::new(&mut w, width as u16, height as u16, &[]).context(
+            GifEncodeSnafu {
+                category: "gif_encoder",
+            },
+        )?;
         encoder
-            .try_encode_frames(frames.into_iter())
-            .context(ImageSnafu {
-                category: "git_encode",
+            .set_repeat(::gif::Repeat::Infinite)
+            .context(GifEncodeSnafu {
+                category: "gif_set_repeat",
+            })?;
+        for frame in frames {
+            let frame = frame.context(ImageSnafu {
+                category: "gif_frame",
             })?;
+            let (numer, denom) = frame.delay().numer_denom_ms();
+            let left = frame.left() as u16;
+            let top = frame.top() as u16;
+            let info: ImageInfo = frame.into_buffer().into();
+            let (palette, mut pixels) =
+                info.quantize_palette_with_colors(100, dithering, max_colors)?;
+            // gif只支持一个透明色，所有透明的颜色都使用它
+            let transparent = palette.iter().position(|color| color.a < 128);
+            if let Some(index) = transparent {
+                for pixel in pixels.iter_mut() {
+                    if palette[*pixel as usize].a < 128 {
+                        *pixel = index as u8;
+                    }
+                }
+            }
+            let gif_frame = ::gif::Frame {
+                left,
+                top,
+                width: info.width as u16,
+                height: info.height as u16,
+                // gif的延时单位为10ms
+                delay: (numer as f32 / denom as f32 / 10.0).round() as u16,
+                dispose: ::gif::DisposalMethod::Background,
+                transparent: transparent.map(|index| index as u8),
+                palette: Some(palette.iter().flat_map(|c| [c.r, c.g, c.b]).collect()),
+                buffer: pixels.into(),
+                ..Default::default()
+            };
+            encoder.write_frame(&gif_frame).context(GifEncodeSnafu {
+                category: "gif_encode",
+            })?;
+        }
     }
 
     Ok(w)
 }
 
 impl ImageInfo {
+    /// Convert the pixels to rgba image.
+    pub fn to_rgba_image(&self) -> RgbaImage {
+        // 像素数量与宽高一致，因此不会失败
+        RgbaImage::from_raw(
+            self.width as u32,
+            self.height as u32,
+            self.buffer.as_bytes().to_vec(),
+        )
+        .unwrap_or_default()
+    }
+    /// Convert the image info to dynamic image.
+    pub fn into_dynamic_image(self) -> DynamicImage {
+        DynamicImage::ImageRgba8(self.to_rgba_image())
+    }
+    /// Whether the image is grayscale, every pixel has the same red, green and blue.
+    pub fn is_grayscale(&self) -> bool {
+        self.buffer.iter().all(|p| p.r == p.g && p.g == p.b)
+    }
     // 转换获取rgb颜色
     fn get_rgb8(&self) -> Vec<RGB8> {
         let mut output_data: Vec<RGB8> = Vec::with_capacity(self.width * self.height);
@@ -202,31 +1127,141 @@ impl ImageInfo {
 
         output_data
     }
-    /// Optimize image to png, the quality is min 0, max 100, which means best effort,
-    /// and never aborts the process.
-    pub fn to_png(&self, quality: u8) -> Result<Vec<u8>> {
+    // 转换获取灰度值
+    fn get_luma8(&self) -> Vec<u8> {
+        let mut output_data: Vec<u8> = Vec::with_capacity(self.width * self.height);
+
+        for ele in &self.buffer {
+            let luma = (ele.r as u32 * 299 + ele.g as u32 * 587 + ele.b as u32 * 114) / 1000;
+            output_data.push(luma as u8)
+        }
+
+        output_data
+    }
+    // 量化颜色，返回调色板以及各像素对应的索引
+    fn quantize_palette(&self, quality: u8, dithering: f32) -> Result<(Vec<RGBA8>, Vec<u8>)> {
+        self.quantize_palette_with_colors(quality, dithering, 256)
+    }
+    fn quantize_palette_with_colors(
+        &self,
+        quality: u8,
+        dithering: f32,
+        max_colors: u32,
+    ) -> Result<(Vec<RGBA8>, Vec<u8>)> {
+        self.quantize_palette_with_speed(0, quality, dithering, max_colors, None)
+    }
+    // 使用neuquant量化，速度更快但质量较低，不支持抖动以及质量的设置
+    fn quantize_palette_neuquant(&self, max_colors: usize) -> (Vec<RGBA8>, Vec<u8>) {
+        let pixels = self.buffer.as_bytes();
+        // 采样因子越大越快，10为推荐值
+        let nq = color_quant::NeuQuant::new(10, max_colors, pixels);
+        let palette = nq
+            .color_map_rgba()
+            .chunks_exact(4)
+            .map(|item| RGBA8::new(item[0], item[1], item[2], item[3]))
+            .collect();
+        let indexes = pixels
+            .chunks_exact(4)
+            .map(|item| nq.index_of(item) as u8)
+            .collect();
+        (palette, indexes)
+    }
+    // 指定最低质量以及量化速度，速度为空则使用默认值
+    fn quantize_palette_with_speed(
+        &self,
+        min_quality: u8,
+        quality: u8,
+        dithering: f32,
+        max_colors: u32,
+        speed: Option<i32>,
+    ) -> Result<(Vec<RGBA8>, Vec<u8>)> {
+        check_quality(quality)?;
         let mut liq = imagequant::new();
-        liq.set_quality(0, quality).context(ImageQuantSnafu {
-            category: "png_set_quality",
+        liq.set_quality(min_quality, quality)
+            .context(ImageQuantSnafu {
+                category: "quantize_set_quality",
+            })?;
+        liq.set_max_colors(max_colors).context(ImageQuantSnafu {
+            category: "quantize_set_max_colors",
         })?;
+        if let Some(speed) = speed {
+            liq.set_speed(speed).context(ImageQuantSnafu {
+                category: "quantize_set_speed",
+            })?;
+        }
 
         let mut img = liq
             .new_image(self.buffer.as_ref(), self.width, self.height, 0.0)
             .context(ImageQuantSnafu {
-                category: "png_new_image",
+                category: "quantize_new_image",
             })?;
 
         let mut res = liq.quantize(&mut img).context(ImageQuantSnafu {
-            category: "png_quantize",
+            category: "quantize",
         })?;
 
-        res.set_dithering_level(1.0).context(ImageQuantSnafu {
-            category: "png_set_level",
-        })?;
+        res.set_dithering_level(dithering)
+            .context(ImageQuantSnafu {
+                category: "quantize_set_level",
+            })?;
 
         let (palette, pixels) = res.remapped(&mut img).context(ImageQuantSnafu {
-            category: "png_remapped",
+            category: "quantize_remapped",
         })?;
+        Ok((palette, pixels))
+    }
+    /// Quantize the colors of image, the quality is min 0, max 100 as `to_png`.
+    /// The result is still rgba image, but only has the colors of palette.
+    pub fn quantize(&self, quality: u8) -> Result<ImageInfo> {
+        // 不使用抖动，避免引入噪点导致压缩率下降
+        let (palette, pixels) = self.quantize_palette(quality, 0.0)?;
+        let buffer = pixels
+            .iter()
+            .map(|index| palette[*index as usize])
+            .collect();
+        Ok(ImageInfo {
+            buffer,
+            width: self.width,
+            height: self.height,
+        })
+    }
+    /// Optimize image to png, the quality is min 0, max 100, which means best effort,
+    /// and never aborts the process.
+    pub fn to_png(&self, quality: u8) -> Result<Vec<u8>> {
+        self.to_png_with_options(quality, &EncodeOptions::new())
+    }
+    /// Optimize image to png with the encode options, see `ENCODE_OPTION_KEYS`.
+    pub fn to_png_with_options(&self, quality: u8, options: &EncodeOptions) -> Result<Vec<u8>> {
+        check_quality(quality)?;
+        warn_unknown_options(options);
+        let dithering = encode_option::<f32>(options, "png.dithering")?.unwrap_or(1.0);
+        let min_quality = encode_option::<u8>(options, "png.min_quality")?.unwrap_or(0);
+        let speed = encode_option::<i32>(options, "png.speed")?;
+        let (palette, pixels) = match options.get("png.quantizer").map(|value| value.trim()) {
+            None | Some("imagequant") => self.quantize_palette_with_speed(
+                min_quality,
+                quality,
+                dithering.clamp(0.0, 1.0),
+                256,
+                speed,
+            )?,
+            Some("neuquant") => {
+                // 仅imagequant支持的参数
+                for key in ["png.dithering", "png.min_quality", "png.speed"] {
+                    if let Some(value) = options.get(key) {
+                        return EncodeOptionSnafu { key, value }.fail();
+                    }
+                }
+                self.quantize_palette_neuquant(256)
+            }
+            Some(value) => {
+                return EncodeOptionSnafu {
+                    key: "png.quantizer",
+                    value,
+                }
+                .fail()
+            }
+        };
         let mut enc = lodepng::Encoder::new();
         enc.set_palette(&palette).context(LodePNGSnafu {
             category: "png_encoder",
@@ -240,7 +1275,113 @@ impl ImageInfo {
 
         Ok(buf)
     }
+    /// Reduce the colors of image to the max colors with median cut,
+    /// unlike `quantize` the count of colors is not limited to 256.
+    pub fn reduce_colors(&self, max_colors: usize) -> ImageInfo {
+        let mut counts: HashMap<RGBA8, u64> = HashMap::new();
+        for pixel in self.buffer.iter() {
+            *counts.entry(*pixel).or_default() += 1;
+        }
+        if counts.len() <= max_colors.max(1) {
+            return ImageInfo {
+                buffer: self.buffer.clone(),
+                width: self.width,
+                height: self.height,
+            };
+        }
+        let channel = |color: &RGBA8, index: usize| match index {
+            0 => color.r,
+            1 => color.g,
+            2 => color.b,
+            _ => color.a,
+        };
+        // 获取范围最大的通道以及其范围
+        let widest = |colors: &[(RGBA8, u64)]| {
+            (0..4)
+                .map(|index| {
+                    let values = colors.iter().map(|(color, _)| channel(color, index));
+                    let range =
+                        values.clone().max().unwrap_or_default() - values.min().unwrap_or_default();
+                    (index, range)
+                })
+                .max_by_key(|(_, range)| *range)
+                .unwrap_or_default()
+        };
+        // 缓存每个box范围最大的通道，每次切分只需计算新的两个box
+        let colors = counts.into_iter().collect::<Vec<_>>();
+        let mut boxes = vec![(widest(&colors), colors)];
+        while boxes.len() < max_colors {
+            let Some((box_index, (index, _))) = boxes
+                .iter()
+                .map(|(widest, _)| *widest)
+                .enumerate()
+                .filter(|(_, (_, range))| *range > 0)
+                .max_by_key(|(_, (_, range))| *range)
+            else {
+                break;
+            };
+            let (_, mut colors) = boxes.swap_remove(box_index);
+            colors.sort_by_key(|(color, _)| channel(color, index));
+            // 按像素数量的中位数切分
+            let total: u64 = colors.iter().map(|(_, count)| count).sum();
+            let mut sum = 0;
+            let mut at = colors.len() - 1;
+            for (i, (_, count)) in colors.iter().enumerate() {
+                sum += count;
+                if sum * 2 >= total {
+                    at = i + 1;
+                    break;
+                }
+            }
+            let at = at.clamp(1, colors.len() - 1);
+            let rest = colors.split_off(at);
+            boxes.push((widest(&colors), colors));
+            boxes.push((widest(&rest), rest));
+        }
+        let mut mapping = HashMap::new();
+        for (_, colors) in boxes {
+            let total: u64 = colors.iter().map(|(_, count)| count).sum();
+            let average = |index: usize| {
+                let sum: u64 = colors
+                    .iter()
+                    .map(|(color, count)| channel(color, index) as u64 * count)
+                    .sum();
+                ((sum + total / 2) / total) as u8
+            };
+            let color = RGBA8::new(average(0), average(1), average(2), average(3));
+            for (item, _) in colors.iter() {
+                mapping.insert(*item, color);
+            }
+        }
+        ImageInfo {
+            buffer: self.buffer.iter().map(|pixel| mapping[pixel]).collect(),
+            width: self.width,
+            height: self.height,
+        }
+    }
+    /// Encode the image to truecolor png without palette,
+    /// it's lossless and keeps all the colors of image.
+    pub fn to_png_truecolor(&self) -> Result<Vec<u8>> {
+        let mut enc = lodepng::Encoder::new();
+        enc.set_auto_convert(false);
+        enc.info_raw_mut().set_colortype(lodepng::ColorType::RGBA);
+        enc.info_raw_mut().set_bitdepth(8);
+        enc.info_png_mut()
+            .color
+            .set_colortype(lodepng::ColorType::RGBA);
+        enc.info_png_mut().color.set_bitdepth(8);
+        let buf = enc
+            .encode(&self.buffer, self.width, self.height)
+            .context(LodePNGSnafu {
+                category: "png_encode",
+            })?;
+        Ok(buf)
+    }
     /// Optimize image to lossless webp.
+    /// The webp encoder only supports lossless mode, so there is no
+    /// color or alpha quality for it.
+    /// Lossless webp has no monochrome mode, the grayscale image is
+    /// encoded as well as possible by the subtract green transform.
     pub fn to_webp(&self) -> Result<Vec<u8>> {
         let mut w = Vec::new();
 
@@ -258,37 +1399,244 @@ impl ImageInfo {
 
         Ok(w)
     }
+    /// Quantize the colors of image before encoding to lossless webp,
+    /// it trades fidelity for size and fits the image with a few colors.
+    pub fn to_webp_quantized(&self, quality: u8) -> Result<Vec<u8>> {
+        self.quantize(quality)?.to_webp()
+    }
     /// Optimize image to avif.
     /// `speed` accepts a value in the range 0-10, where 0 is the slowest and 10 is the fastest.
     /// `quality` accepts a value in the range 0-100, where 0 is the worst and 100 is the best.
     pub fn to_avif(&self, quality: u8, speed: u8) -> Result<Vec<u8>> {
-        let mut w = Vec::new();
+        self.to_avif_with_alpha_quality(quality, quality, speed)
+    }
+    /// Optimize image to avif with each of the speeds and keep the smallest one,
+    /// only the first `MAX_SPEED_ATTEMPTS` speeds are tried.
+    /// The webp encoder is lossless only and has no effort levels,
+    /// so there is no such search for webp.
+    pub fn to_avif_smallest(&self, quality: u8, speeds: &[u8]) -> Result<Vec<u8>> {
+        self.to_avif_smallest_with_options(quality, speeds, &EncodeOptions::new())
+    }
+    /// Optimize image to avif with each of the speeds as `to_avif_smallest`,
+    /// and the encode options are applied to every attempt.
+    pub fn to_avif_smallest_with_options(
+        &self,
+        quality: u8,
+        speeds: &[u8],
+        options: &EncodeOptions,
+    ) -> Result<Vec<u8>> {
+        let mut result: Option<Vec<u8>> = None;
+        for speed in speeds.iter().take(MAX_SPEED_ATTEMPTS) {
+            let data = self.to_avif_with_options(quality, *speed, options)?;
+            if result.as_ref().is_none_or(|item| data.len() < item.len()) {
+                result = Some(data);
+            }
+        }
+        match result {
+            Some(data) => Ok(data),
+            None => self.to_avif_with_options(quality, 0, options),
+        }
+    }
+    /// Optimize image to avif with the encode options, see `ENCODE_OPTION_KEYS`.
+    pub fn to_avif_with_options(
+        &self,
+        quality: u8,
+        speed: u8,
+        options: &EncodeOptions,
+    ) -> Result<Vec<u8>> {
+        warn_unknown_options(options);
+        self.encode_avif(quality, quality, speed, options)
+    }
+    /// Optimize image to avif with the best quality and the rgb color model,
+    /// it's near lossless because the av1 encoder has no lossless coding.
+    pub fn to_avif_lossless(&self, speed: u8) -> Result<Vec<u8>> {
+        let options = EncodeOptions::from([("avif.color_model".to_string(), "rgb".to_string())]);
+        self.encode_avif(100, 100, speed, &options)
+    }
+    /// Optimize image to avif with a separate quality for the alpha channel,
+    /// `alpha_quality` accepts a value in the range 0-100 as `quality`.
+    pub fn to_avif_with_alpha_quality(
+        &self,
+        quality: u8,
+        alpha_quality: u8,
+        speed: u8,
+    ) -> Result<Vec<u8>> {
+        self.encode_avif(quality, alpha_quality, speed, &EncodeOptions::new())
+    }
+    fn encode_avif(
+        &self,
+        quality: u8,
+        alpha_quality: u8,
+        speed: u8,
+        options: &EncodeOptions,
+    ) -> Result<Vec<u8>> {
+        check_quality(quality)?;
+        check_quality(alpha_quality)?;
         let mut sp = speed;
         if sp == 0 {
             sp = 3;
         }
+        if encode_option::<bool>(options, "avif.monochrome")?.unwrap_or_default()
+            && self.is_grayscale()
+        {
+            return self.encode_avif_monochrome(quality, alpha_quality, sp.min(10));
+        }
 
-        let img = avif::AvifEncoder::new_with_speed_quality(&mut w, sp, quality);
-        img.write_image(
-            self.buffer.as_bytes(),
-            self.width as u32,
-            self.height as u32,
-            image::ColorType::Rgba8.into(),
-        )
-        .context(ImageSnafu {
-            category: "avif_encode",
-        })?;
+        let mut encoder = ravif::Encoder::new()
+            .with_quality(quality as f32)
+            .with_alpha_quality(alpha_quality as f32)
+            .with_speed(sp.min(10))
+            .with_bit_depth(ravif::BitDepth::Eight);
+        if let Some(value) = encode_option::<String>(options, "avif.color_model")? {
+            let color_model = match value.as_str() {
+                "ycbcr" => ravif::ColorModel::YCbCr,
+                "rgb" => ravif::ColorModel::RGB,
+                _ => {
+                    return EncodeOptionSnafu {
+                        key: "avif.color_model",
+                        value,
+                    }
+                    .fail()
+                }
+            };
+            encoder = encoder.with_internal_color_model(color_model);
+        }
+        if let Some(value) = encode_option::<String>(options, "avif.alpha_color_mode")? {
+            let mode = match value.as_str() {
+                "dirty" => ravif::AlphaColorMode::UnassociatedDirty,
+                "clean" => ravif::AlphaColorMode::UnassociatedClean,
+                "premultiplied" => ravif::AlphaColorMode::Premultiplied,
+                _ => {
+                    return EncodeOptionSnafu {
+                        key: "avif.alpha_color_mode",
+                        value,
+                    }
+                    .fail()
+                }
+            };
+            encoder = encoder.with_alpha_color_mode(mode);
+        }
+        if let Some(threads) = encode_option::<usize>(options, "avif.threads")? {
+            encoder = encoder.with_num_threads(Some(threads));
+        }
+        let result = encoder
+            .encode_rgba(ravif::Img::new(
+                self.buffer.as_slice(),
+                self.width,
+                self.height,
+            ))
+            .context(RavifSnafu {
+                category: "avif_encode",
+            })?;
 
-        Ok(w)
+        Ok(result.avif_file)
+    }
+    // 以单色编码avif，只保留亮度平面，有透明度时透明通道单独编码
+    fn encode_avif_monochrome(&self, quality: u8, alpha_quality: u8, speed: u8) -> Result<Vec<u8>> {
+        let luma: Vec<u8> = self.buffer.iter().map(|p| p.g).collect();
+        let color = encode_av1_plane(self.width, self.height, &luma, quality, speed).context(
+            RavifSnafu {
+                category: "avif_encode",
+            },
+        )?;
+        let alpha = if self.buffer.iter().any(|p| p.a != 255) {
+            let alpha: Vec<u8> = self.buffer.iter().map(|p| p.a).collect();
+            let data = encode_av1_plane(self.width, self.height, &alpha, alpha_quality, speed)
+                .context(RavifSnafu {
+                    category: "avif_encode",
+                })?;
+            Some(data)
+        } else {
+            None
+        };
+
+        Ok(avif_serialize::Aviffy::new()
+            .set_seq_profile(0)
+            .set_monochrome(true)
+            .set_chroma_subsampling((true, true))
+            .to_vec(
+                &color,
+                alpha.as_deref(),
+                self.width as u32,
+                self.height as u32,
+                8,
+            ))
     }
     /// Optimize image to jpeg, the quality 60-80 are recommended.
     pub fn to_mozjpeg(&self, quality: u8) -> Result<Vec<u8>> {
-        let mut comp = mozjpeg::Compress::new(mozjpeg::ColorSpace::JCS_RGB);
+        self.to_mozjpeg_with_options(quality, &EncodeOptions::new())
+    }
+    /// Optimize image to jpeg with the encode options, see `ENCODE_OPTION_KEYS`.
+    pub fn to_mozjpeg_with_options(&self, quality: u8, options: &EncodeOptions) -> Result<Vec<u8>> {
+        warn_unknown_options(options);
+        self.encode_mozjpeg(
+            mozjpeg::ColorSpace::JCS_RGB,
+            self.get_rgb8().as_bytes(),
+            quality,
+            options,
+        )
+    }
+    /// Optimize image to single channel grayscale jpeg,
+    /// it should be used for the image which has no color.
+    pub fn to_mozjpeg_gray(&self, quality: u8) -> Result<Vec<u8>> {
+        self.to_mozjpeg_gray_with_options(quality, &EncodeOptions::new())
+    }
+    /// Optimize image to grayscale jpeg with the encode options, see `ENCODE_OPTION_KEYS`.
+    pub fn to_mozjpeg_gray_with_options(
+        &self,
+        quality: u8,
+        options: &EncodeOptions,
+    ) -> Result<Vec<u8>> {
+        warn_unknown_options(options);
+        self.encode_mozjpeg(
+            mozjpeg::ColorSpace::JCS_GRAYSCALE,
+            &self.get_luma8(),
+            quality,
+            options,
+        )
+    }
+    fn encode_mozjpeg(
+        &self,
+        color_space: mozjpeg::ColorSpace,
+        scanlines: &[u8],
+        quality: u8,
+        options: &EncodeOptions,
+    ) -> Result<Vec<u8>> {
+        check_quality(quality)?;
+        let mut comp = mozjpeg::Compress::new(color_space);
         comp.set_size(self.width, self.height);
         comp.set_quality(quality as f32);
+        // 默认为progressive，关闭扫描优化则为baseline
+        if let Some(progressive) = encode_option::<bool>(options, "jpeg.progressive")? {
+            if progressive {
+                comp.set_progressive_mode();
+            } else {
+                comp.set_optimize_scans(false);
+            }
+        }
+        if let Some(value) = options.get("jpeg.subsampling") {
+            let sampling = match value.trim() {
+                "444" => (1, 1),
+                "422" => (2, 1),
+                "420" => (2, 2),
+                _ => {
+                    return EncodeOptionSnafu {
+                        key: "jpeg.subsampling",
+                        value,
+                    }
+                    .fail()
+                }
+            };
+            // 灰度图片无色度通道
+            if color_space != mozjpeg::ColorSpace::JCS_GRAYSCALE {
+                comp.set_chroma_sampling_pixel_sizes(sampling, sampling);
+            }
+        }
+        if let Some(smoothing) = encode_option::<u8>(options, "jpeg.smoothing")? {
+            comp.set_smoothing_factor(smoothing.min(100));
+        }
         let mut comp = comp.start_compress(Vec::new()).context(IoSnafu {})?;
-        comp.write_scanlines(self.get_rgb8().as_bytes())
-            .context(IoSnafu {})?;
+        comp.write_scanlines(scanlines).context(IoSnafu {})?;
         let data = comp.finish().context(IoSnafu {})?;
         Ok(data)
     }
@@ -296,8 +1644,15 @@ impl ImageInfo {
 
 #[cfg(test)]
 mod tests {
-    use super::{load, ImageInfo};
+    use super::{
+        decode_frames, embed_icc_profile, exif_thumbnail, icc_profile, jpeg_lossless, load,
+        minify_svg, preview, probe_animation, to_animated_webp, to_gif, to_gif_with_palette,
+        to_gif_with_playback_rate, AnimationInfo, EncodeOptions, ImageInfo,
+    };
     use pretty_assertions::assert_eq;
+    use rgb::RGBA8;
+    use std::collections::HashSet;
+    use std::time::Duration;
 
     use std::io::Cursor;
     fn load_image() -> ImageInfo {
@@ -312,6 +1667,52 @@ mod tests {
         assert_eq!(img.width, 144);
     }
     #[test]
+    fn test_to_rgba_image() {
+        let data = include_bytes!("../assets/rust-logo.png");
+        let rgba = image::load_from_memory(data).unwrap().to_rgba8();
+        let img: ImageInfo = rgba.clone().into();
+        assert_eq!(img.to_rgba_image(), rgba);
+        let di = img.into_dynamic_image();
+        assert_eq!(di.to_rgba8(), rgba);
+    }
+    #[test]
+    fn test_png_quantizer() {
+        // 渐变的大图片
+        let img: ImageInfo = image::RgbaImage::from_fn(256, 256, |x, y| {
+            image::Rgba([x as u8, y as u8, ((x + y) / 2) as u8, 255])
+        })
+        .into();
+        for quantizer in ["imagequant", "neuquant"] {
+            let mut options = EncodeOptions::new();
+            options.insert("png.quantizer".to_string(), quantizer.to_string());
+            let data = img.to_png_with_options(80, &options).unwrap();
+            // 调色板的png，IHDR的颜色类型为3
+            assert_eq!(3, data[25]);
+            assert_eq!(true, data.windows(4).any(|item| item == b"PLTE"));
+            let di = image::load_from_memory(&data).unwrap();
+            assert_eq!((256, 256), (di.width(), di.height()));
+            let pixel = di.to_rgba8().get_pixel(150, 50).0;
+            assert_eq!(true, (pixel[0] as i32 - 150).abs() < 16);
+            assert_eq!(
+                "Quality 200 is invalid, it should be 0-100",
+                img.to_png_with_options(200, &options)
+                    .err()
+                    .unwrap()
+                    .to_string()
+            );
+        }
+        let mut options = EncodeOptions::new();
+        options.insert("png.quantizer".to_string(), "neuquant".to_string());
+        options.insert("png.speed".to_string(), "3".to_string());
+        assert_eq!(
+            "Encode option png.speed is invalid, value:3",
+            img.to_png_with_options(80, &options)
+                .err()
+                .unwrap()
+                .to_string()
+        );
+    }
+    #[test]
     fn test_to_png() {
         let img = load_image();
         let result = img.to_png(90).unwrap();
@@ -319,21 +1720,574 @@ mod tests {
         assert_eq!(result.len(), 1742);
     }
     #[test]
+    fn test_quality_invalid() {
+        let img = load_image();
+        for result in [
+            img.to_png(101),
+            img.to_avif(101, 8),
+            img.to_avif_with_alpha_quality(100, 101, 8),
+            img.to_mozjpeg(101),
+            img.to_mozjpeg_gray(101),
+        ] {
+            assert_eq!(
+                "Quality 101 is invalid, it should be 0-100",
+                result.err().unwrap().to_string()
+            );
+        }
+        assert!(img.to_png(100).is_ok());
+        assert!(img.to_mozjpeg(100).is_ok());
+        assert!(img.to_mozjpeg_gray(100).is_ok());
+    }
+    #[test]
+    fn test_exif_thumbnail() {
+        let jpeg = |width, height, color| {
+            let buffer = vec![color; width * height];
+            ImageInfo {
+                buffer,
+                width,
+                height,
+            }
+            .to_mozjpeg(90)
+            .unwrap()
+        };
+        let thumbnail = jpeg(
+            16,
+            12,
+            RGBA8 {
+                r: 255,
+                g: 0,
+                b: 0,
+                a: 255,
+            },
+        );
+        let data = jpeg(
+            160,
+            120,
+            RGBA8 {
+                r: 0,
+                g: 0,
+                b: 255,
+                a: 255,
+            },
+        );
+        // tiff头，空的IFD0，IFD1包含缩略图的位置与长度
+        let mut tiff = b"II*\0".to_vec();
+        tiff.extend_from_slice(&8_u32.to_le_bytes());
+        tiff.extend_from_slice(&0_u16.to_le_bytes());
+        tiff.extend_from_slice(&14_u32.to_le_bytes());
+        tiff.extend_from_slice(&2_u16.to_le_bytes());
+        for (tag, value) in [(0x0201_u16, 44_u32), (0x0202, thumbnail.len() as u32)] {
+            tiff.extend_from_slice(&tag.to_le_bytes());
+            tiff.extend_from_slice(&4_u16.to_le_bytes());
+            tiff.extend_from_slice(&1_u32.to_le_bytes());
+            tiff.extend_from_slice(&value.to_le_bytes());
+        }
+        tiff.extend_from_slice(&0_u32.to_le_bytes());
+        tiff.extend_from_slice(&thumbnail);
+        let mut with_exif = data[..2].to_vec();
+        with_exif.extend_from_slice(&[0xff, 0xe1]);
+        with_exif.extend_from_slice(&(tiff.len() as u16 + 8).to_be_bytes());
+        with_exif.extend_from_slice(b"Exif\0\0");
+        with_exif.extend_from_slice(&tiff);
+        with_exif.extend_from_slice(&data[2..]);
+
+        let di = exif_thumbnail(&with_exif).unwrap().unwrap();
+        assert_eq!((16, 12), (di.width(), di.height()));
+        // 预览使用缩略图而非原图
+        let di = preview(&with_exif, 16).unwrap();
+        assert_eq!((16, 12), (di.width(), di.height()));
+        assert_eq!(true, di.to_rgb8().get_pixel(8, 6)[0] > 200);
+
+        // 无缩略图或缩略图过小则使用原图
+        assert_eq!(true, exif_thumbnail(&data).unwrap().is_none());
+        let di = preview(&data, 40).unwrap();
+        assert_eq!((40, 30), (di.width(), di.height()));
+        let di = preview(&with_exif, 40).unwrap();
+        assert_eq!((40, 30), (di.width(), di.height()));
+        assert_eq!(true, di.to_rgb8().get_pixel(20, 15)[2] > 200);
+    }
+    #[test]
+    fn test_jpeg_lossless() {
+        let data = include_bytes!("../assets/rust-logo.png");
+        let img = load(Cursor::new(data), "png").unwrap();
+        // 先生成非优化的baseline jpeg
+        let mut jpeg = vec![];
+        image::codecs::jpeg::JpegEncoder::new_with_quality(&mut jpeg, 90)
+            .encode_image(&image::DynamicImage::ImageRgba8(img.to_rgba_image()).to_rgb8())
+            .unwrap();
+        let result = jpeg_lossless(&jpeg).unwrap();
+        assert_eq!(true, result.len() <= jpeg.len());
+        let expected = image::load_from_memory(&jpeg).unwrap().to_rgb8();
+        let actual = image::load_from_memory(&result).unwrap().to_rgb8();
+        assert_eq!(expected, actual);
+
+        let result = jpeg_lossless(&jpeg[..20]);
+        assert_eq!(
+            true,
+            result
+                .err()
+                .unwrap()
+                .to_string()
+                .starts_with("Handle image fail, category:jpegtran")
+        );
+    }
+    #[test]
     fn test_to_webp() {
         let img = load_image();
         let result = img.to_webp().unwrap();
         assert_eq!(result.len(), 2764);
     }
     #[test]
+    fn test_to_webp_quantized() {
+        // 四个色块，带有少量噪点
+        let img: ImageInfo = image::RgbaImage::from_fn(128, 128, |x, y| {
+            let noise = ((x * 7 + y * 13) % 5) as u8;
+            let base = match (x < 64, y < 64) {
+                (true, true) => [200, 30, 30],
+                (false, true) => [30, 200, 30],
+                (true, false) => [30, 30, 200],
+                (false, false) => [220, 220, 220],
+            };
+            image::Rgba([base[0] + noise, base[1] + noise, base[2] + noise, 255])
+        })
+        .into();
+        let plain = img.to_webp().unwrap();
+        let result = img.to_webp_quantized(30).unwrap();
+        assert_eq!(true, result.len() < plain.len());
+    }
+    #[test]
+    fn test_monochrome() {
+        let img: ImageInfo = image::RgbaImage::from_fn(96, 96, |x, y| {
+            let v = ((x * 2 + y) % 256) as u8 ^ ((x * y) % 7) as u8;
+            image::Rgba([v, v, v, 255])
+        })
+        .into();
+        assert_eq!(true, img.is_grayscale());
+        let mut options = EncodeOptions::new();
+        options.insert("avif.monochrome".to_string(), "true".to_string());
+
+        let color = img.to_avif(80, 8).unwrap();
+        let mono = img.to_avif_with_options(80, 8, &options).unwrap();
+        assert_eq!(true, mono.len() < color.len());
+
+        // 彩色图片忽略单色选项
+        let mut img = img;
+        img.buffer[0] = RGBA8::new(255, 0, 0, 255);
+        assert_eq!(false, img.is_grayscale());
+        assert_eq!(
+            img.to_avif(80, 8).unwrap(),
+            img.to_avif_with_options(80, 8, &options).unwrap()
+        );
+    }
+    #[test]
     fn test_to_jpeg() {
         let img = load_image();
         let result = img.to_mozjpeg(90).unwrap();
         assert_eq!(result.len(), 392);
     }
     #[test]
+    fn test_to_jpeg_gray() {
+        let img = load_image();
+        let result = img.to_mozjpeg_gray(90).unwrap();
+        let di = image::load_from_memory(&result).unwrap();
+        assert_eq!(di.color(), image::ColorType::L8);
+        assert_eq!(di.width(), 144);
+    }
+    #[test]
+    fn test_encode_options() {
+        let img = load_image();
+        let options = |key: &str, value: &str| {
+            let mut options = EncodeOptions::new();
+            options.insert(key.to_string(), value.to_string());
+            options
+        };
+        // progressive的jpeg使用SOF2，baseline使用SOF0
+        let progressive = img
+            .to_mozjpeg_with_options(90, &options("jpeg.progressive", "true"))
+            .unwrap();
+        assert_eq!(
+            true,
+            progressive.windows(2).any(|item| item == [0xff, 0xc2])
+        );
+        let baseline = img
+            .to_mozjpeg_with_options(90, &options("jpeg.progressive", "false"))
+            .unwrap();
+        assert_eq!(true, baseline.windows(2).any(|item| item == [0xff, 0xc0]));
+        assert_eq!(false, baseline.windows(2).any(|item| item == [0xff, 0xc2]));
+        assert_ne!(progressive.len(), baseline.len());
+
+        // 红蓝相间的条纹，色度不采样时保留更多的颜色边缘
+        let stripes: ImageInfo = image::RgbaImage::from_fn(64, 64, |x, _| {
+            if x % 2 == 0 {
+                image::Rgba([255, 0, 0, 255])
+            } else {
+                image::Rgba([0, 0, 255, 255])
+            }
+        })
+        .into();
+        let full = stripes
+            .to_mozjpeg_with_options(90, &options("jpeg.subsampling", "444"))
+            .unwrap();
+        let half = stripes
+            .to_mozjpeg_with_options(90, &options("jpeg.subsampling", "420"))
+            .unwrap();
+        assert_eq!(true, full.len() > half.len());
+        assert_eq!(half, stripes.to_mozjpeg(90).unwrap());
+        let decoded = image::load_from_memory(&full).unwrap().to_rgb8();
+        assert_eq!(true, decoded.get_pixel(10, 10)[0] > 200);
+        assert_eq!(true, decoded.get_pixel(11, 10)[2] > 200);
+        let result = stripes.to_mozjpeg_with_options(90, &options("jpeg.subsampling", "411"));
+        assert_eq!(
+            "Encode option jpeg.subsampling is invalid, value:411",
+            result.err().unwrap().to_string()
+        );
+        // 灰度图片忽略色度采样
+        let result = img
+            .to_mozjpeg_gray_with_options(90, &options("jpeg.subsampling", "444"))
+            .unwrap();
+        assert_eq!(img.to_mozjpeg_gray(90).unwrap(), result);
+
+        let plain = img.to_png(90).unwrap();
+        let result = img
+            .to_png_with_options(90, &options("png.dithering", "0"))
+            .unwrap();
+        assert_ne!(plain, result);
+        assert_ne!(plain.len(), result.len());
+        let result = img
+            .to_png_with_options(90, &options("png.dithering", "1"))
+            .unwrap();
+        assert_eq!(plain, result);
+
+        // 默认速度为4
+        let result = img
+            .to_png_with_options(90, &options("png.speed", "4"))
+            .unwrap();
+        assert_eq!(plain, result);
+        let result = img
+            .to_png_with_options(90, &options("png.speed", "10"))
+            .unwrap();
+        assert_ne!(plain, result);
+        let result = img.to_png_with_options(90, &options("png.speed", "11"));
+        assert_eq!(
+            "Handle image fail, category:quantize_set_speed, message:VALUE_OUT_OF_RANGE",
+            result.err().unwrap().to_string()
+        );
+        let result = img.to_png_with_options(90, &options("png.quantizer", "octree"));
+        assert_eq!(
+            "Encode option png.quantizer is invalid, value:octree",
+            result.err().unwrap().to_string()
+        );
+        let result = img.to_png_with_options(10, &options("png.min_quality", "100"));
+        assert_eq!(
+            true,
+            result
+                .err()
+                .unwrap()
+                .to_string()
+                .starts_with("Handle image fail, category:quantize_set_quality")
+        );
+
+        // 未知的选项忽略
+        let result = img
+            .to_mozjpeg_with_options(90, &options("jpeg.unknown", "1"))
+            .unwrap();
+        assert_eq!(img.to_mozjpeg(90).unwrap(), result);
+
+        let result = img.to_mozjpeg_with_options(90, &options("jpeg.progressive", "yes"));
+        assert_eq!(
+            "Encode option jpeg.progressive is invalid, value:yes",
+            result.err().unwrap().to_string()
+        );
+    }
+    #[test]
     fn test_to_avif() {
         let img = load_image();
         let result = img.to_avif(90, 3).unwrap();
         assert_eq!(result.len(), 2345);
     }
+    #[test]
+    fn test_to_avif_smallest() {
+        let img = load_image();
+        let speeds = [6, 8, 10];
+        let result = img.to_avif_smallest(70, &speeds).unwrap();
+        for speed in speeds {
+            assert_eq!(true, result.len() <= img.to_avif(70, speed).unwrap().len());
+        }
+    }
+    #[test]
+    fn test_to_avif_with_alpha_quality() {
+        let img = load_image();
+        let result = img.to_avif_with_alpha_quality(90, 90, 8).unwrap();
+        assert_eq!(result, img.to_avif(90, 8).unwrap());
+        let low_alpha = img.to_avif_with_alpha_quality(90, 10, 8).unwrap();
+        assert_ne!(result.len(), low_alpha.len());
+    }
+
+    #[test]
+    fn test_minify_svg() {
+        let data = r##"<?xml version="1.0" encoding="UTF-8"?>
+<!-- Generator: test -->
+<svg xmlns="http://www.w3.org/2000/svg" width="100" height="100" viewBox="0 0 100 100">
+    <metadata>
+        <description>This is the metadata of svg</description>
+    </metadata>
+    <g transform="translate(10.000000, 10.000000)">
+        <g transform="scale(2.000000)">
+            <rect x="5.000000" y="5.000000" width="20.000000" height="20.000000" fill="#FF0000"/>
+        </g>
+    </g>
+</svg>"##;
+        let minified = minify_svg(data.as_bytes(), 3).unwrap();
+        assert_eq!(true, minified.len() < data.len());
+        assert_eq!(
+            false,
+            String::from_utf8_lossy(&minified).contains("metadata")
+        );
+
+        let render = |data: &[u8]| {
+            let tree = usvg::Tree::from_data(data, &usvg::Options::default()).unwrap();
+            let mut pixmap = resvg::tiny_skia::Pixmap::new(100, 100).unwrap();
+            resvg::render(&tree, Default::default(), &mut pixmap.as_mut());
+            pixmap.data().to_vec()
+        };
+        assert_eq!(render(data.as_bytes()), render(&minified));
+
+        let data = r##"<svg xmlns="http://www.w3.org/2000/svg" width="100" height="100">
+    <rect x="5" y="5" width="20" height="20" fill="#FF0000"/>
+    <text x="10" y="50">Hello</text>
+</svg>"##;
+        assert_eq!(
+            "Handle image fail, category:svg, message:text is not supported",
+            minify_svg(data.as_bytes(), 3).err().unwrap().to_string()
+        );
+    }
+
+    #[test]
+    fn test_embed_webp_icc() {
+        let frames: Vec<image::Frame> = [[255, 0, 0, 255], [0, 0, 255, 255]]
+            .iter()
+            .map(|color| {
+                image::Frame::from_parts(
+                    image::RgbaImage::from_pixel(4, 4, image::Rgba(*color)),
+                    0,
+                    0,
+                    image::Delay::from_numer_denom_ms(100, 1),
+                )
+            })
+            .collect();
+        let data = to_animated_webp(&frames, 0, 100).unwrap();
+        let icc = b"fake icc profile".repeat(3);
+        let result = embed_icc_profile(&data, "webp", &icc).unwrap();
+        // vp8x的chunk大小不变，只设置icc标记
+        assert_eq!(&data[12..20], &result[12..20]);
+        assert_eq!(0x32, result[20]);
+        assert_eq!(Some(icc), icc_profile(&result).unwrap());
+        let frames = decode_frames(Cursor::new(&result), image::ImageFormat::WebP).unwrap();
+        assert_eq!(2, frames.len());
+        assert_eq!(
+            &image::Rgba([0, 0, 255, 255]),
+            frames[1].buffer().get_pixel(0, 0)
+        );
+    }
+
+    #[test]
+    fn test_probe_animation() {
+        let new_gif = |delays: &[u16], repeat| {
+            let mut data = vec![];
+            let palette = [255, 0, 0, 0, 0, 255];
+            let mut encoder = ::gif::Encoder::new(&mut data, 4, 4, &palette).unwrap();
+            encoder.set_repeat(repeat).unwrap();
+            for (index, delay) in delays.iter().enumerate() {
+                let frame = ::gif::Frame {
+                    width: 4,
+                    height: 4,
+                    delay: *delay,
+                    buffer: vec![(index % 2) as u8; 16].into(),
+                    ..Default::default()
+                };
+                encoder.write_frame(&frame).unwrap();
+            }
+            drop(encoder);
+            data
+        };
+        let data = new_gif(&[5, 5, 10], ::gif::Repeat::Infinite);
+        assert_eq!(
+            Some(AnimationInfo {
+                frames: 3,
+                loop_count: 0,
+                duration: Duration::from_millis(200),
+            }),
+            probe_animation(&data, "gif")
+        );
+        let data = new_gif(&[5, 5], ::gif::Repeat::Finite(2));
+        assert_eq!(3, probe_animation(&data, "gif").unwrap().loop_count);
+        // 单帧不是动图
+        let data = new_gif(&[5], ::gif::Repeat::Infinite);
+        assert_eq!(None, probe_animation(&data, "gif"));
+
+        // webp只需要chunk的头部信息，帧数据无需有效
+        let chunk = |name: &[u8], payload: Vec<u8>| {
+            let mut data = name.to_vec();
+            data.extend((payload.len() as u32).to_le_bytes());
+            data.extend(&payload);
+            if payload.len() % 2 == 1 {
+                data.push(0);
+            }
+            data
+        };
+        let mut body = b"WEBP".to_vec();
+        body.extend(chunk(b"VP8X", vec![0x12, 0, 0, 0, 3, 0, 0, 3, 0, 0]));
+        body.extend(chunk(b"ANIM", vec![0, 0, 0, 0, 2, 0]));
+        for delay in [100_u32, 250] {
+            let mut anmf = vec![0; 12];
+            anmf.extend(&delay.to_le_bytes()[..3]);
+            anmf.extend([0x02, 1, 2, 3]);
+            body.extend(chunk(b"ANMF", anmf));
+        }
+        let data = chunk(b"RIFF", body);
+        assert_eq!(
+            Some(AnimationInfo {
+                frames: 2,
+                loop_count: 2,
+                duration: Duration::from_millis(350),
+            }),
+            probe_animation(&data, "webp")
+        );
+
+        // apng的帧数由acTL指定，延时由各fcTL指定
+        let png_chunk = |name: &[u8], payload: Vec<u8>| {
+            let mut data = (payload.len() as u32).to_be_bytes().to_vec();
+            data.extend(name);
+            data.extend(&payload);
+            data.extend([0; 4]);
+            data
+        };
+        let fctl = |numerator: u16, denominator: u16| {
+            let mut payload = vec![0; 20];
+            payload.extend(numerator.to_be_bytes());
+            payload.extend(denominator.to_be_bytes());
+            payload.extend([0, 0]);
+            png_chunk(b"fcTL", payload)
+        };
+        let mut data = b"\x89PNG\r\n\x1a\n".to_vec();
+        data.extend(png_chunk(b"IHDR", vec![0; 13]));
+        data.extend(png_chunk(
+            b"acTL",
+            [2_u32, 0].map(u32::to_be_bytes).concat(),
+        ));
+        data.extend(fctl(1, 10));
+        data.extend(png_chunk(b"IDAT", vec![0; 4]));
+        data.extend(fctl(30, 0));
+        data.extend(png_chunk(b"fdAT", vec![0; 8]));
+        data.extend(png_chunk(b"IEND", vec![]));
+        assert_eq!(
+            Some(AnimationInfo {
+                frames: 2,
+                loop_count: 0,
+                duration: Duration::from_millis(400),
+            }),
+            probe_animation(&data, "png")
+        );
+
+        let data = include_bytes!("../assets/rust-logo.png");
+        assert_eq!(None, probe_animation(data, "png"));
+        assert_eq!(None, probe_animation(data, "jpeg"));
+    }
+    #[test]
+    fn test_to_gif_delay() {
+        let mut data = vec![];
+        {
+            let palette = [255, 0, 0, 0, 0, 255];
+            let mut encoder = ::gif::Encoder::new(&mut data, 4, 4, &palette).unwrap();
+            for (index, delay) in [10, 30].into_iter().enumerate() {
+                let frame = ::gif::Frame {
+                    width: 4,
+                    height: 4,
+                    delay,
+                    buffer: vec![index as u8; 16].into(),
+                    ..Default::default()
+                };
+                encoder.write_frame(&frame).unwrap();
+            }
+        }
+        let get_delays = |data: &[u8]| {
+            let mut decoder = ::gif::DecodeOptions::new().read_info(data).unwrap();
+            let mut delays = vec![];
+            while let Some(frame) = decoder.read_next_frame().unwrap() {
+                delays.push(frame.delay);
+            }
+            delays
+        };
+
+        let result = to_gif(Cursor::new(&data), 10).unwrap();
+        assert_eq!(vec![10, 30], get_delays(&result));
+
+        let result = to_gif_with_playback_rate(Cursor::new(&data), 10, 2.0).unwrap();
+        assert_eq!(vec![5, 15], get_delays(&result));
+    }
+
+    #[test]
+    fn test_reduce_colors() {
+        let mut buffer = vec![];
+        for y in 0..64_u32 {
+            for x in 0..64_u32 {
+                buffer.push(RGBA8::new(
+                    (x * 4) as u8,
+                    (y * 4) as u8,
+                    ((x + y) * 2) as u8,
+                    255,
+                ));
+            }
+        }
+        let img = ImageInfo {
+            buffer,
+            width: 64,
+            height: 64,
+        };
+        let count = |img: &ImageInfo| img.buffer.iter().collect::<HashSet<_>>().len();
+        assert_eq!(4096, count(&img));
+
+        let result = img.reduce_colors(1000);
+        assert_eq!(true, count(&result) <= 1000);
+        assert_eq!(true, count(&result) > 256);
+
+        let data = result.to_png_truecolor().unwrap();
+        // IHDR的颜色类型为6(rgba)
+        assert_eq!(6, data[25]);
+        let decoded = load(Cursor::new(data), "png").unwrap();
+        assert_eq!(result.buffer, decoded.buffer);
+    }
+
+    #[test]
+    fn test_to_gif_with_palette() {
+        let mut data = vec![];
+        {
+            let mut encoder = image::codecs::gif::GifEncoder::new(&mut data);
+            for offset in [0, 64] {
+                let frame = image::RgbaImage::from_fn(32, 32, |x, y| {
+                    image::Rgba([(x * 8) as u8, (y * 8) as u8, (x * y + offset) as u8, 255])
+                });
+                encoder.encode_frame(image::Frame::new(frame)).unwrap();
+            }
+        }
+        let count_frames = |data: &[u8]| {
+            let mut decoder = ::gif::DecodeOptions::new().read_info(data).unwrap();
+            let mut count = 0;
+            while decoder.read_next_frame().unwrap().is_some() {
+                count += 1;
+            }
+            count
+        };
+
+        let full = to_gif(Cursor::new(&data), 10).unwrap();
+        let result = to_gif_with_palette(Cursor::new(&data), 4, 0.0).unwrap();
+        assert_eq!(true, result.len() < full.len());
+        assert_eq!(2, count_frames(&result));
+
+        let result = to_gif_with_palette(Cursor::new(&data), 1, 0.0);
+        assert_eq!(
+            "Palette size 1 is invalid, it should be 2-256",
+            result.err().unwrap().to_string()
+        );
+    }
 }